@@ -1,3 +1,4 @@
+use std::convert::TryFrom;
 use std::ops;
 
 use bytes::{Buf, BufMut};
@@ -18,26 +19,30 @@ use crate::coding::{Codec, UnexpectedEnd};
 //  | 11   | 8      | 62          | 0-4611686018427387903 |
 //  +------+--------+-------------+-----------------------+
 
-const ONE_OCTET_MAX: u64 = 63;
-const TWO_OCTETS_MIN: u64 = ONE_OCTET_MAX + 1;
-const TWO_OCTETS_MAX: u64 = 16383;
-const FOUR_OCTETS_MIN: u64 = TWO_OCTETS_MAX + 1;
-const FOUR_OCTETS_MAX: u64 = 1_073_741_823;
-const EIGHT_OCTETS_MIN: u64 = FOUR_OCTETS_MAX + 1;
 const EIGHT_OCTETS_MAX: u64 = 4_611_686_018_427_387_903;
 
 #[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct VarInt(u64);
 
 impl VarInt {
+    /// The largest value that can be represented by a QUIC varint, `2^62 - 1`.
+    pub const MAX: VarInt = VarInt(EIGHT_OCTETS_MAX);
+
     pub fn size(&self) -> usize {
-        match self.0 {
-            0...ONE_OCTET_MAX => 1,
-            TWO_OCTETS_MIN...TWO_OCTETS_MAX => 2,
-            FOUR_OCTETS_MIN...FOUR_OCTETS_MAX => 4,
-            EIGHT_OCTETS_MIN...EIGHT_OCTETS_MAX => 8,
-            _ => unreachable!(),
-        }
+        size(self.0).expect("VarInt is always within the representable range")
+    }
+
+    /// Adds `rhs`, returning `None` on overflow or if the sum exceeds `VarInt::MAX`.
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        self.0
+            .checked_add(rhs.0)
+            .filter(|&sum| sum <= EIGHT_OCTETS_MAX)
+            .map(VarInt)
+    }
+
+    /// Adds `rhs`, clamping to `VarInt::MAX` on overflow.
+    pub fn saturating_add(self, rhs: Self) -> Self {
+        self.checked_add(rhs).unwrap_or(Self::MAX)
     }
 }
 
@@ -71,15 +76,23 @@ impl From<u32> for VarInt {
     }
 }
 
-impl From<u64> for VarInt {
-    fn from(int: u64) -> Self {
-        debug_assert!(int <= EIGHT_OCTETS_MAX);
-        VarInt(int)
+impl TryFrom<u64> for VarInt {
+    type Error = WriteError;
+
+    fn try_from(int: u64) -> Result<Self, Self::Error> {
+        if int <= EIGHT_OCTETS_MAX {
+            Ok(VarInt(int))
+        } else {
+            Err(WriteError::OversizedValue)
+        }
     }
 }
-impl From<usize> for VarInt {
-    fn from(int: usize) -> Self {
-        Self::from(int as u64)
+
+impl TryFrom<usize> for VarInt {
+    type Error = WriteError;
+
+    fn try_from(int: usize) -> Result<Self, Self::Error> {
+        Self::try_from(int as u64)
     }
 }
 
@@ -87,8 +100,7 @@ impl ops::Add for VarInt {
     type Output = Self;
 
     fn add(self, rhs: Self) -> Self::Output {
-        let sum = self.0 + rhs.0;
-        VarInt::from(sum)
+        self.checked_add(rhs).expect("VarInt overflow")
     }
 }
 
@@ -110,11 +122,11 @@ impl ops::Add<VarInt> for usize {
 
 impl Codec for VarInt {
     fn decode<B: Buf>(buf: &mut B) -> Result<Self, UnexpectedEnd> {
-        unimplemented!()
+        read(buf).map(VarInt).ok_or(UnexpectedEnd)
     }
 
     fn encode<B: BufMut>(&self, buf: &mut B) {
-        unimplemented!()
+        write(self.0, buf).expect("buffer did not have enough capacity")
     }
 }
 pub fn size(x: u64) -> Option<usize> {
@@ -175,28 +187,259 @@ pub enum WriteError {
 }
 
 pub fn write<W: BufMut>(x: u64, w: &mut W) -> Result<(), WriteError> {
-    if x < 2u64.pow(6) {
-        if w.remaining_mut() < 1 {
-            return Err(WriteError::InsufficientSpace);
+    let len = size(x).ok_or(WriteError::OversizedValue)?;
+    if w.remaining_mut() < len {
+        return Err(WriteError::InsufficientSpace);
+    }
+    write_unchecked(x, len, w);
+    Ok(())
+}
+
+/// Writes `x`, already known to fit in `len` bytes, assuming the caller has already confirmed
+/// `w.remaining_mut() >= len`. `bytes_mut()` only promises a slice of the *current* chunk, which
+/// can be shorter than `len` even though the total remaining capacity isn't, so this copies in
+/// as many chunks as `w` hands back rather than indexing a single `bytes_mut()` call by `len`.
+fn write_unchecked<W: BufMut>(x: u64, len: usize, w: &mut W) {
+    let tag = (len.trailing_zeros() as u64) << (len * 8 - 2);
+    let bytes = (x | tag).to_be_bytes();
+    let mut src = &bytes[8 - len..];
+    while !src.is_empty() {
+        let n = unsafe {
+            let dst = w.bytes_mut();
+            let n = src.len().min(dst.len());
+            dst[..n].copy_from_slice(&src[..n]);
+            w.advance_mut(n);
+            n
+        };
+        src = &src[n..];
+    }
+}
+
+/// Largest value that fits in a 62-bit varint after zig-zag encoding.
+pub const SIGNED_MAX: i64 = (1i64 << 61) - 1;
+/// Smallest value that fits in a 62-bit varint after zig-zag encoding.
+pub const SIGNED_MIN: i64 = -(1i64 << 61);
+
+// `n`'s sign bit, smeared across all 64 bits by the arithmetic shift, flips every bit of `n << 1`
+// when `n` is negative, turning `-1, -2, ...` into the odd numbers `1, 3, ...` interleaved with
+// the even numbers `0, 2, ...` from the non-negative half.
+fn zigzag_encode(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+fn zigzag_decode(u: u64) -> i64 {
+    ((u >> 1) as i64) ^ -((u & 1) as i64)
+}
+
+/// Number of bytes a zig-zag encoded `n` would occupy, or `None` if `n` is outside
+/// `SIGNED_MIN..=SIGNED_MAX`.
+pub fn size_signed(n: i64) -> Option<usize> {
+    if n < SIGNED_MIN || n > SIGNED_MAX {
+        return None;
+    }
+    size(zigzag_encode(n))
+}
+
+/// Reads a zig-zag encoded varint, e.g. an ACK range gap or timestamp delta.
+pub fn read_signed<R: Buf>(r: &mut R) -> Option<i64> {
+    read(r).map(zigzag_decode)
+}
+
+/// Writes `n` as a zig-zag encoded varint, failing if it falls outside `SIGNED_MIN..=SIGNED_MAX`.
+pub fn write_signed<W: BufMut>(n: i64, w: &mut W) -> Result<(), WriteError> {
+    if n < SIGNED_MIN || n > SIGNED_MAX {
+        return Err(WriteError::OversizedValue);
+    }
+    write(zigzag_encode(n), w)
+}
+
+/// Classifies the length of the varint beginning at `buf[0]` from its two high bits, without
+/// decoding the payload. Returns `None` if `buf` is empty.
+pub fn peek(buf: &[u8]) -> Option<usize> {
+    let tag = buf.first()? >> 6;
+    Some(1 << tag)
+}
+
+/// Returns the decoded value alongside the number of bytes of `buf` it occupied, so the caller
+/// can advance its own cursor without decoding into an intermediate `Buf`.
+pub fn decode_slice(buf: &[u8]) -> Option<(VarInt, usize)> {
+    let len = peek(buf)?;
+    let bytes = buf.get(..len)?;
+    let mut tmp = [0; 8];
+    tmp[8 - len..].copy_from_slice(bytes);
+    tmp[8 - len] &= 0b0011_1111;
+    Some((VarInt(BigEndian::read_u64(&tmp)), len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use std::io::Cursor;
+
+    // One value on each side of every octet-length boundary.
+    const ROUNDTRIP_VALUES: &[u64] = &[
+        0,
+        63,
+        64,
+        16_383,
+        16_384,
+        1_073_741_823,
+        1_073_741_824,
+        EIGHT_OCTETS_MAX,
+    ];
+
+    /// A `BufMut` whose `bytes_mut()` never exposes more than one byte of its spare capacity at
+    /// a time, even though `remaining_mut()` reports the full reserved capacity. Stands in for
+    /// a chunked buffer (e.g. `Chain`) to make sure `write_unchecked` doesn't assume its whole
+    /// write fits in a single `bytes_mut()` slice.
+    struct OneByteAtATime(Vec<u8>);
+
+    impl OneByteAtATime {
+        fn with_capacity(cap: usize) -> Self {
+            OneByteAtATime(Vec::with_capacity(cap))
         }
-        w.put_u8(x as u8);
-    } else if x < 2u64.pow(14) {
-        if w.remaining_mut() < 2 {
-            return Err(WriteError::InsufficientSpace);
+    }
+
+    impl BufMut for OneByteAtATime {
+        fn remaining_mut(&self) -> usize {
+            self.0.capacity() - self.0.len()
         }
-        w.put_u16_be(0b01 << 14 | x as u16);
-    } else if x < 2u64.pow(30) {
-        if w.remaining_mut() < 4 {
-            return Err(WriteError::InsufficientSpace);
+
+        unsafe fn advance_mut(&mut self, cnt: usize) {
+            let len = self.0.len() + cnt;
+            self.0.set_len(len);
         }
-        w.put_u32_be(0b10 << 30 | x as u32);
-    } else if x < 2u64.pow(62) {
-        if w.remaining_mut() < 8 {
-            return Err(WriteError::InsufficientSpace);
+
+        unsafe fn bytes_mut(&mut self) -> &mut [u8] {
+            let avail = self.remaining_mut().min(1);
+            std::slice::from_raw_parts_mut(self.0.as_mut_ptr().add(self.0.len()), avail)
         }
-        w.put_u64_be(0b11 << 62 | x);
-    } else {
-        return Err(WriteError::OversizedValue);
     }
-    Ok(())
+
+    #[test]
+    fn write_read_roundtrip() {
+        for &x in ROUNDTRIP_VALUES {
+            let mut buf = BytesMut::new();
+            write(x, &mut buf).unwrap();
+            assert_eq!(buf.len(), size(x).unwrap());
+            let mut cursor = Cursor::new(&buf[..]);
+            assert_eq!(read(&mut cursor), Some(x));
+        }
+    }
+
+    #[test]
+    fn write_does_not_assume_a_single_contiguous_chunk() {
+        for &x in ROUNDTRIP_VALUES {
+            let mut buf = OneByteAtATime::with_capacity(8);
+            write(x, &mut buf).unwrap();
+            let mut cursor = Cursor::new(&buf.0[..]);
+            assert_eq!(read(&mut cursor), Some(x));
+        }
+    }
+
+    #[test]
+    fn zigzag_roundtrip() {
+        for &n in &[0, 1, -1, SIGNED_MAX, SIGNED_MIN] {
+            assert_eq!(zigzag_decode(zigzag_encode(n)), n);
+
+            let mut buf = BytesMut::new();
+            write_signed(n, &mut buf).unwrap();
+            assert_eq!(buf.len(), size_signed(n).unwrap());
+            let mut cursor = Cursor::new(&buf[..]);
+            assert_eq!(read_signed(&mut cursor), Some(n));
+        }
+    }
+
+    #[test]
+    fn zigzag_rejects_out_of_range() {
+        assert_eq!(size_signed(SIGNED_MAX + 1), None);
+        assert_eq!(size_signed(SIGNED_MIN - 1), None);
+
+        let mut buf = BytesMut::new();
+        assert_eq!(
+            write_signed(SIGNED_MAX + 1, &mut buf),
+            Err(WriteError::OversizedValue)
+        );
+        assert_eq!(
+            write_signed(SIGNED_MIN - 1, &mut buf),
+            Err(WriteError::OversizedValue)
+        );
+    }
+
+    #[test]
+    fn decode_slice_agrees_with_read() {
+        for &x in ROUNDTRIP_VALUES {
+            let mut buf = BytesMut::new();
+            write(x, &mut buf).unwrap();
+
+            let mut cursor = Cursor::new(&buf[..]);
+            let read_value = read(&mut cursor).unwrap();
+
+            let (decoded, len) = decode_slice(&buf).unwrap();
+            assert_eq!(u64::from(decoded), read_value);
+            assert_eq!(len, buf.len());
+        }
+    }
+
+    #[test]
+    fn decode_slice_rejects_empty_and_truncated() {
+        assert_eq!(peek(&[]), None);
+        assert_eq!(decode_slice(&[]), None);
+
+        // Tag `01` claims a 2-byte varint, but only one byte is present.
+        let truncated = [0b0100_0000];
+        assert_eq!(peek(&truncated), Some(2));
+        assert_eq!(decode_slice(&truncated), None);
+    }
+
+    #[test]
+    fn try_from_rejects_out_of_range() {
+        assert_eq!(VarInt::try_from(EIGHT_OCTETS_MAX).unwrap(), VarInt::MAX);
+        assert_eq!(
+            VarInt::try_from(EIGHT_OCTETS_MAX + 1),
+            Err(WriteError::OversizedValue)
+        );
+        assert_eq!(
+            VarInt::try_from(EIGHT_OCTETS_MAX as usize + 1),
+            Err(WriteError::OversizedValue)
+        );
+    }
+
+    #[test]
+    fn checked_add_rejects_overflow() {
+        let one = VarInt::from(1u8);
+        assert_eq!(VarInt::MAX.checked_add(one), None);
+        assert_eq!(VarInt::MAX.checked_add(VarInt::MAX), None);
+        assert_eq!(VarInt::from(1u8).checked_add(one), Some(VarInt::from(2u8)));
+    }
+
+    #[test]
+    fn saturating_add_clamps_to_max() {
+        assert_eq!(VarInt::MAX.saturating_add(VarInt::from(1u8)), VarInt::MAX);
+    }
+
+    #[test]
+    #[should_panic]
+    fn add_panics_on_overflow() {
+        let _ = VarInt::MAX + VarInt::from(1u8);
+    }
+
+    #[test]
+    fn codec_roundtrip() {
+        for &x in ROUNDTRIP_VALUES {
+            let mut buf = BytesMut::new();
+            VarInt(x).encode(&mut buf);
+            let mut cursor = Cursor::new(&buf[..]);
+            assert_eq!(VarInt::decode(&mut cursor).unwrap(), VarInt(x));
+        }
+    }
+
+    #[test]
+    fn codec_decode_rejects_truncated_input() {
+        let mut buf = BytesMut::new();
+        VarInt::from(16_384u32).encode(&mut buf);
+        let mut cursor = Cursor::new(&buf[..1]);
+        assert!(VarInt::decode(&mut cursor).is_err());
+    }
 }